@@ -30,13 +30,38 @@
 
 extern crate iron;
 
-use std::io::{self, Read};
+use std::cmp;
+use std::io::Read;
+use std::time::{Duration, Instant};
 use iron::prelude::*;
-use iron::headers::Connection;
+use iron::headers::{Connection, ContentLength, ContentType, Encoding, TransferEncoding};
+use iron::method::Method;
 use iron::middleware::AfterMiddleware;
+use iron::mime::{Mime, SubLevel, TopLevel};
+use iron::status;
+
+/// Size of the buffer used to drain the body in bounded chunks.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// The result of a single `Drain::drain` call, reported to an `on_drain` callback.
+#[derive(Debug, Clone, Copy)]
+pub struct DrainOutcome {
+    /// How many bytes of the body were read.
+    pub bytes: u64,
+    /// Whether the body was larger than `limit` (or the drain deadline passed first).
+    pub truncated: bool,
+    /// Whether the connection was forced closed as a result of this drain.
+    pub closed: bool,
+}
 
 /// Iron middleware that makes sure requests are read in full before reusing sockets
-pub struct Drain { limit: u64 }
+pub struct Drain {
+    limit: u64,
+    timeout: Option<Duration>,
+    reject_over_limit: bool,
+    on_drain: Option<Box<Fn(&DrainOutcome) + Send + Sync>>,
+    content_type_limits: Vec<(Mime, u64)>,
+}
 
 impl Drain {
     /// Create a Drain with the default limit (1MB)
@@ -47,27 +72,189 @@ impl Drain {
     /// Create a Drain with a custom limit
     pub fn with_limit(limit: u64) -> Drain {
         Drain {
-            limit: limit
+            limit: limit,
+            timeout: None,
+            reject_over_limit: false,
+            on_drain: None,
+            content_type_limits: Vec::new(),
+        }
+    }
+
+    /// Create a Drain with a custom limit and a deadline on the total time spent draining.
+    ///
+    /// The deadline is only checked between chunk reads, so it bounds a client that keeps
+    /// trickling bytes in slowly. It cannot interrupt a single `read` call that never returns
+    /// (e.g. a client that sends one byte and then goes silent) — that requires a read timeout
+    /// on the underlying socket, which is outside this middleware's control.
+    pub fn with_timeout(limit: u64, timeout: Duration) -> Drain {
+        Drain {
+            limit: limit,
+            timeout: Some(timeout),
+            reject_over_limit: false,
+            on_drain: None,
+            content_type_limits: Vec::new(),
+        }
+    }
+
+    /// Reject over-limit bodies with a `413 Payload Too Large` response instead of silently
+    /// closing the connection.
+    ///
+    /// The default behavior (close the connection and keep the handler's response) is unchanged
+    /// unless this is called.
+    pub fn reject_over_limit(mut self) -> Drain {
+        self.reject_over_limit = true;
+        self
+    }
+
+    /// Register a callback invoked with the outcome of every drain, e.g. to log or emit metrics
+    /// for unexpected-body and oversized-body events.
+    pub fn on_drain(mut self, callback: Box<Fn(&DrainOutcome) + Send + Sync>) -> Drain {
+        self.on_drain = Some(callback);
+        self
+    }
+
+    /// Override the default limit for requests whose `Content-Type` matches, checked in order
+    /// (first match wins). Patterns may use top- or sub-level wildcards, e.g. `multipart/*`.
+    ///
+    /// Requests with no matching entry (or no `Content-Type` header) keep using `limit`.
+    pub fn with_content_type_limits(mut self, limits: Vec<(Mime, u64)>) -> Drain {
+        self.content_type_limits = limits;
+        self
+    }
+
+    /// Close the connection, replacing the response with a `413` if `reject_over_limit` is set.
+    ///
+    /// When rejecting, this deliberately discards whatever response the handler produced
+    /// (headers included) — the body was never fully read, so nothing the handler wrote can be
+    /// delivered correctly anyway, and the client needs an unambiguous `413` instead.
+    fn close_over_limit(&self, resp: &mut Response) {
+        if self.reject_over_limit {
+            // `status` is iron's re-exported hyper `StatusCode`; `PayloadTooLarge` is the name
+            // hyper gives the 413 reason phrase on the hyper version this crate pins.
+            *resp = Response::with(status::PayloadTooLarge);
         }
+        resp.headers.set(Connection::close());
+    }
+
+    fn report(&self, outcome: DrainOutcome) {
+        if let Some(ref callback) = self.on_drain {
+            callback(&outcome);
+        }
+    }
+
+    /// Report that the body was consumed exactly, with nothing left over.
+    fn report_clean_end(&self, consumed: u64) {
+        self.report(DrainOutcome { bytes: consumed, truncated: false, closed: false });
+    }
+
+    /// Close the connection after a genuine read error (not an over-limit body) and report it.
+    fn close_on_read_error(&self, resp: &mut Response, consumed: u64) {
+        resp.headers.set(Connection::close());
+        self.report(DrainOutcome { bytes: consumed, truncated: false, closed: true });
+    }
+
+    /// The limit that applies to this request: the first `content_type_limits` entry whose
+    /// pattern matches the request's `Content-Type`, or `self.limit` if none do.
+    fn limit_for(&self, req: &Request) -> u64 {
+        let content_type = req.headers.get::<ContentType>().map(|ct| &ct.0);
+        if let Some(mime) = content_type {
+            for &(ref pattern, limit) in &self.content_type_limits {
+                if mime_matches(pattern, mime) {
+                    return limit;
+                }
+            }
+        }
+        self.limit
     }
 
     fn drain(&self, req: &mut Request, resp: &mut Response) {
-        // try reading up to the limit
-        if io::copy(&mut req.body.by_ref().take(self.limit), &mut io::sink()).is_ok() {
-            // see if there's anything left
-            let mut buf = [0];
-            if let Ok(n) = req.body.read(&mut buf) {
-                if n == 0 {
+        let is_chunked = req.headers.get::<TransferEncoding>()
+            .map_or(false, |te| te.0.contains(&Encoding::Chunked));
+        let content_length = req.headers.get::<ContentLength>().map(|cl| cl.0);
+        let limit = self.limit_for(req);
+
+        // bodyless methods with no Content-Length or chunked encoding never have a body worth
+        // draining
+        if content_length.is_none() && !is_chunked {
+            match req.method {
+                Method::Get | Method::Head | Method::Delete => {
+                    self.report(DrainOutcome { bytes: 0, truncated: false, closed: false });
                     return;
                 }
+                _ => {}
             }
         }
 
-        // there's too much data or an error occurred, so just close the connection
-        resp.headers.set(Connection::close());
+        // a declared length beyond the limit means we already know we'll have to close, so
+        // don't bother reading it first
+        if let Some(len) = content_length {
+            if len > limit {
+                self.close_over_limit(resp);
+                self.report(DrainOutcome { bytes: 0, truncated: true, closed: true });
+                return;
+            }
+        }
+
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let mut buf = [0; CHUNK_SIZE];
+        let mut remaining = limit;
+        let mut consumed: u64 = 0;
+
+        loop {
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    // ran out of time before the body was fully consumed
+                    self.close_over_limit(resp);
+                    self.report(DrainOutcome { bytes: consumed, truncated: true, closed: true });
+                    return;
+                }
+            }
+
+            if remaining == 0 {
+                break;
+            }
+
+            let want = cmp::min(buf.len() as u64, remaining) as usize;
+            match req.body.read(&mut buf[..want]) {
+                Ok(0) => {
+                    self.report_clean_end(consumed);
+                    return;
+                }
+                Ok(n) => {
+                    consumed += n as u64;
+                    remaining -= n as u64;
+                }
+                Err(_) => {
+                    self.close_on_read_error(resp, consumed);
+                    return;
+                }
+            }
+        }
+
+        // see if there's anything left
+        let mut probe = [0];
+        match req.body.read(&mut probe) {
+            Ok(0) => self.report_clean_end(consumed),
+            Ok(_) => {
+                // there's too much data, so reject or close per policy
+                self.close_over_limit(resp);
+                self.report(DrainOutcome { bytes: consumed, truncated: true, closed: true });
+            }
+            Err(_) => self.close_on_read_error(resp, consumed),
+        }
     }
 }
 
+/// Whether `pattern`'s top- and sub-level match `actual`, treating a `Star` in `pattern` as a
+/// wildcard at either level. A `Star` in `actual` is not a wildcard.
+fn mime_matches(pattern: &Mime, actual: &Mime) -> bool {
+    let &Mime(ref pattern_top, ref pattern_sub, _) = pattern;
+    let &Mime(ref actual_top, ref actual_sub, _) = actual;
+
+    (*pattern_top == TopLevel::Star || pattern_top == actual_top) &&
+    (*pattern_sub == SubLevel::Star || pattern_sub == actual_sub)
+}
+
 impl AfterMiddleware for Drain {
     fn after(&self, req: &mut Request, mut resp: Response) -> IronResult<Response> {
         self.drain(req, &mut resp);
@@ -80,3 +267,78 @@ impl AfterMiddleware for Drain {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn mime(top: TopLevel, sub: SubLevel) -> Mime {
+        Mime(top, sub, vec![])
+    }
+
+    #[test]
+    fn mime_matches_exact() {
+        assert!(mime_matches(&mime(TopLevel::Application, SubLevel::Json),
+                              &mime(TopLevel::Application, SubLevel::Json)));
+    }
+
+    #[test]
+    fn mime_matches_top_level_wildcard() {
+        assert!(mime_matches(&mime(TopLevel::Star, SubLevel::Star),
+                              &mime(TopLevel::Multipart, SubLevel::FormData)));
+    }
+
+    #[test]
+    fn mime_matches_sub_level_wildcard() {
+        assert!(mime_matches(&mime(TopLevel::Multipart, SubLevel::Star),
+                              &mime(TopLevel::Multipart, SubLevel::FormData)));
+    }
+
+    #[test]
+    fn mime_matches_rejects_different_top_level() {
+        assert!(!mime_matches(&mime(TopLevel::Multipart, SubLevel::Star),
+                               &mime(TopLevel::Application, SubLevel::Json)));
+    }
+
+    #[test]
+    fn close_over_limit_default_just_closes() {
+        let drain = Drain::with_limit(10);
+        let mut resp = Response::new();
+        drain.close_over_limit(&mut resp);
+        assert_eq!(resp.headers.get::<Connection>(), Some(&Connection::close()));
+        assert_ne!(resp.status, Some(status::PayloadTooLarge));
+    }
+
+    #[test]
+    fn close_over_limit_reject_returns_413() {
+        let drain = Drain::with_limit(10).reject_over_limit();
+        let mut resp = Response::new();
+        drain.close_over_limit(&mut resp);
+        assert_eq!(resp.status, Some(status::PayloadTooLarge));
+        assert_eq!(resp.headers.get::<Connection>(), Some(&Connection::close()));
+    }
+
+    #[test]
+    fn on_drain_reports_truncated_outcome() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_clone = seen.clone();
+        let drain = Drain::with_limit(10).on_drain(Box::new(move |outcome| {
+            seen_clone.lock().unwrap().push(*outcome);
+        }));
+
+        drain.report(DrainOutcome { bytes: 42, truncated: true, closed: true });
+
+        let recorded = seen.lock().unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].bytes, 42);
+        assert!(recorded[0].truncated);
+        assert!(recorded[0].closed);
+    }
+
+    #[test]
+    fn report_without_callback_is_a_no_op() {
+        let drain = Drain::with_limit(10);
+        drain.report(DrainOutcome { bytes: 0, truncated: false, closed: false });
+    }
+}
+